@@ -1,10 +1,15 @@
 #![allow(non_upper_case_globals)]
 
 use core_foundation::base::{CFRelease, CFRetain, CFTypeID};
+use core_foundation::mach_port::{CFMachPort, CFMachPortRef};
+use core_foundation::runloop::CFRunLoopSource;
 use geometry::CGPoint;
 use event_source::CGEventSource;
 
 use libc;
+use std::mem::ManuallyDrop;
+use std::panic;
+use std::ptr;
 
 use foreign_types::ForeignType;
 
@@ -41,7 +46,7 @@ bitflags! {
 ///
 /// [Ref](http://opensource.apple.com/source/IOHIDFamily/IOHIDFamily-700/IOHIDSystem/IOKit/hidsystem/IOLLEvent.h)
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CGEventType {
     Null = 0,
 
@@ -82,6 +87,91 @@ pub enum CGMouseButton {
     Center,
 }
 
+/// The unit of measurement for a scroll wheel event's wheel deltas.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum ScrollEventUnit {
+    Pixel = 0,
+    Line = 1,
+}
+
+/// Fields in an event that can be accessed with
+/// `CGEvent::get_integer_value_field`/`get_double_value_field` and their
+/// corresponding setters.
+///
+/// [Ref](https://developer.apple.com/documentation/coregraphics/cgeventfield)
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum EventField {
+    /// Key to access an integer field that contains the mouse button event
+    /// number. Matching mouse-down and mouse-up events will have the same
+    /// event number.
+    MOUSE_EVENT_NUMBER = 0,
+    /// Key to access an integer field that contains the mouse button click
+    /// state. A click state of 1 represents a single click, 2 represents a
+    /// double-click, and 3 represents a triple-click.
+    MOUSE_EVENT_CLICK_STATE = 1,
+    /// Key to access a double field that contains the mouse button pressure.
+    /// The pressure value may range from 0 to 1, with 0 representing the
+    /// mouse being up and 1 representing full pressure.
+    MOUSE_EVENT_PRESSURE = 2,
+    /// Key to access an integer field that contains the mouse button number.
+    MOUSE_EVENT_BUTTON_NUMBER = 3,
+    /// Key to access an integer field that contains the horizontal mouse
+    /// delta since the last mouse movement event.
+    MOUSE_EVENT_DELTA_X = 4,
+    /// Key to access an integer field that contains the vertical mouse delta
+    /// since the last mouse movement event.
+    MOUSE_EVENT_DELTA_Y = 5,
+    /// Key to access an integer field. The value is non-zero if the event
+    /// should be ignored by the Inkwell subsystem.
+    MOUSE_EVENT_INSTANT_MOUSER = 6,
+    /// Key to access an integer field that encodes the mouse event subtype:
+    /// 0 for a default mouse event, 1 for a tablet point event, and 2 for a
+    /// tablet proximity event.
+    MOUSE_EVENT_SUBTYPE = 7,
+    /// Key to access an integer field. The value is non-zero if the keyboard
+    /// event is an autorepeat.
+    KEYBOARD_EVENT_AUTOREPEAT = 8,
+    /// Key to access an integer field that contains the virtual keycode of
+    /// the key-down, key-up, or flags-changed event.
+    KEYBOARD_EVENT_KEYCODE = 9,
+    /// Key to access an integer field that contains the keyboard type
+    /// identifier.
+    KEYBOARD_EVENT_KEYBOARD_TYPE = 10,
+    /// Key to access an integer field that contains the scroll wheel delta
+    /// on the primary (vertical) axis, in units determined by the
+    /// `ScrollEventUnit` used to create the event.
+    SCROLL_WHEEL_EVENT_DELTA_AXIS_1 = 11,
+    /// Key to access an integer field that contains the scroll wheel delta
+    /// on the secondary axis, in the same units as axis 1.
+    SCROLL_WHEEL_EVENT_DELTA_AXIS_2 = 12,
+    /// Key to access an integer field that contains the scroll wheel delta
+    /// on the tertiary axis, in the same units as axis 1.
+    SCROLL_WHEEL_EVENT_DELTA_AXIS_3 = 13,
+    /// Key to access a double field that contains the accumulated scroll
+    /// wheel delta on the primary axis since the last pixel-unit event.
+    SCROLL_WHEEL_EVENT_FIXED_PT_DELTA_AXIS_1 = 93,
+    /// Key to access a double field that contains the accumulated scroll
+    /// wheel delta on the secondary axis since the last pixel-unit event.
+    SCROLL_WHEEL_EVENT_FIXED_PT_DELTA_AXIS_2 = 94,
+    /// Key to access a double field that contains the accumulated scroll
+    /// wheel delta on the tertiary axis since the last pixel-unit event.
+    SCROLL_WHEEL_EVENT_FIXED_PT_DELTA_AXIS_3 = 95,
+    /// Key to access an integer field that contains the Unix (BSD) process
+    /// ID of the process that should receive the event, if it was created
+    /// to be delivered to a specific process.
+    EVENT_TARGET_UNIX_PROCESS_ID = 40,
+    /// Key to access an integer field that contains the Unix (BSD) process
+    /// ID of the process that created the event's source.
+    EVENT_SOURCE_UNIX_PROCESS_ID = 41,
+    /// Key to access a field that contains a custom user-supplied value set
+    /// via `CGEventSetIntegerValueField`, useful for recognizing and
+    /// filtering out an application's own synthetic events.
+    EVENT_SOURCE_USER_DATA = 42,
+}
+
 /// Possible tapping points for events.
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -91,6 +181,198 @@ pub enum CGEventTapLocation {
     AnnotatedSession,
 }
 
+/// Where a new event tap is inserted relative to the taps already installed
+/// at a given location.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum CGEventTapPlacement {
+    HeadInsertEventTap,
+    TailAppendEventTap,
+}
+
+/// Whether an event tap is merely observing the event stream, or is able to
+/// modify and delete events that pass through it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum CGEventTapOptions {
+    Default = 0,
+    ListenOnly = 1,
+}
+
+/// A bitmask specifying a set of `CGEventType`s of interest to an event tap.
+/// Bit `N` of the mask corresponds to `CGEventType` value `N`.
+pub type CGEventMask = u64;
+
+/// Build a `CGEventMask` bit for a single `CGEventType`, e.g.
+/// `CGEventMaskBit(CGEventType::KeyDown)`.
+///
+/// `TapDisabledByTimeout` and `TapDisabledByUserInput` are out-of-band
+/// notifications, not maskable event types; they have no corresponding bit
+/// and this returns `0` for them rather than shifting by their huge
+/// discriminant.
+#[allow(non_snake_case)]
+pub fn CGEventMaskBit(event_type: CGEventType) -> CGEventMask {
+    match event_type {
+        CGEventType::TapDisabledByTimeout | CGEventType::TapDisabledByUserInput => 0,
+        _ => 1 << (event_type as u32),
+    }
+}
+
+/// An opaque handle to the event tap passed in to an event tap callback,
+/// used to post additional events back into the same stream via
+/// `CGEvent::post_to_event_source`.
+pub enum __CGEventTapProxy {}
+pub type CGEventTapProxy = *const __CGEventTapProxy;
+
+/// The signature of the callback passed to `CGEventTapCreate`.
+type CGEventTapCallBackFn = extern "C" fn(
+    proxy: CGEventTapProxy,
+    event_type: CGEventType,
+    event: ::sys::CGEventRef,
+    user_info: *mut libc::c_void,
+) -> ::sys::CGEventRef;
+
+/// The Rust-side callback installed on a `CGEventTap`.
+///
+/// Returning `None` swallows the event, returning `Some` with a modified (or
+/// newly created) event replaces it, and returning the event unchanged
+/// (cloned from the `&CGEvent` argument) forwards it along unmodified.
+pub type CGEventTapCallback = dyn FnMut(CGEventTapProxy, CGEventType, &CGEvent) -> Option<CGEvent>;
+
+struct CGEventTapContext {
+    mach_port: CFMachPortRef,
+    callback: Box<CGEventTapCallback>,
+}
+
+/// A live tap on the system event stream, created with `CGEventTap::new`.
+///
+/// Dropping the tap invalidates the mach port, releases it and the run loop
+/// source, and frees the callback.
+pub struct CGEventTap {
+    mach_port: CFMachPort,
+    run_loop_source: CFRunLoopSource,
+    context: Box<CGEventTapContext>,
+}
+
+impl CGEventTap {
+    /// Install a new event tap at `location`, inserted at `placement`, with
+    /// `options` controlling whether it can modify events, watching for the
+    /// event types set in `events_of_interest` (build with `CGEventMaskBit`).
+    ///
+    /// `callback` is invoked for every matching event, as well as for the
+    /// out-of-band `TapDisabledByTimeout`/`TapDisabledByUserInput` events;
+    /// the tap is automatically re-enabled when either of those is received.
+    /// Panics inside `callback` are caught at the FFI boundary and the event
+    /// is swallowed rather than unwinding into system code.
+    pub fn new<F>(
+        location: CGEventTapLocation,
+        placement: CGEventTapPlacement,
+        options: CGEventTapOptions,
+        events_of_interest: CGEventMask,
+        callback: F,
+    ) -> Result<CGEventTap, ()>
+    where
+        F: FnMut(CGEventTapProxy, CGEventType, &CGEvent) -> Option<CGEvent> + 'static,
+    {
+        let context = Box::into_raw(Box::new(CGEventTapContext {
+            mach_port: ptr::null_mut(),
+            callback: Box::new(callback),
+        }));
+
+        unsafe {
+            let mach_port_ref = CGEventTapCreate(
+                location,
+                placement,
+                options,
+                events_of_interest,
+                cgevent_tap_trampoline,
+                context as *mut libc::c_void,
+            );
+
+            if mach_port_ref.is_null() {
+                drop(Box::from_raw(context));
+                return Err(());
+            }
+
+            (*context).mach_port = mach_port_ref;
+
+            let mach_port = CFMachPort::wrap_under_create_rule(mach_port_ref);
+            let run_loop_source = match mach_port.create_run_loop_source(0) {
+                Ok(source) => source,
+                Err(_) => {
+                    drop(Box::from_raw(context));
+                    return Err(());
+                }
+            };
+
+            Ok(CGEventTap {
+                mach_port,
+                run_loop_source,
+                context: Box::from_raw(context),
+            })
+        }
+    }
+
+    /// The run loop source to add to a `CFRunLoop` (and remove again) to
+    /// actually pump events through the callback.
+    pub fn run_loop_source(&self) -> &CFRunLoopSource {
+        &self.run_loop_source
+    }
+
+    /// Re-enable the tap after it was disabled (by the user, or by the
+    /// system after the callback took too long to return).
+    pub fn enable(&self) {
+        unsafe {
+            CGEventTapEnable(self.mach_port.as_concrete_TypeRef(), true);
+        }
+    }
+
+    /// Disable the tap, stopping delivery of events to the callback.
+    pub fn disable(&self) {
+        unsafe {
+            CGEventTapEnable(self.mach_port.as_concrete_TypeRef(), false);
+        }
+    }
+}
+
+impl Drop for CGEventTap {
+    fn drop(&mut self) {
+        // Invalidate the port before the context is freed below.
+        unsafe {
+            CFMachPortInvalidate(self.mach_port.as_concrete_TypeRef());
+        }
+    }
+}
+
+extern "C" fn cgevent_tap_trampoline(
+    proxy: CGEventTapProxy,
+    event_type: CGEventType,
+    event: ::sys::CGEventRef,
+    user_info: *mut libc::c_void,
+) -> ::sys::CGEventRef {
+    let context = unsafe { &mut *(user_info as *mut CGEventTapContext) };
+
+    if let CGEventType::TapDisabledByTimeout | CGEventType::TapDisabledByUserInput = event_type {
+        unsafe {
+            CGEventTapEnable(context.mach_port, true);
+        }
+    }
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| unsafe {
+        // The tap does not hand off ownership of `event` to the callback, so
+        // wrap it without adopting the retain count `CGEvent`'s `Drop`
+        // (`CFRelease`) would otherwise release on our way out.
+        let event = ManuallyDrop::new(CGEvent::from_ptr(event));
+        (context.callback)(proxy, event_type, &event)
+    }));
+
+    match result {
+        Ok(Some(replacement)) => replacement.into_ptr(),
+        Ok(None) => ptr::null_mut(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
 foreign_type! {
     #[doc(hidden)]
     type CType = ::sys::CGEvent;
@@ -150,6 +432,84 @@ impl CGEvent {
         }
     }
 
+    pub fn new_scroll_event(
+        source: CGEventSource,
+        units: ScrollEventUnit,
+        wheel_count: u32,
+        wheel1: i32,
+        wheel2: i32,
+        wheel3: i32,
+    ) -> Result<CGEvent, ()> {
+        unsafe {
+            let event_ref = CGEventCreateScrollWheelEvent2(
+                source.as_ptr(),
+                units,
+                wheel_count,
+                wheel1,
+                wheel2,
+                wheel3,
+            );
+            if !event_ref.is_null() {
+                Ok(Self::from_ptr(event_ref))
+            } else {
+                Err(())
+            }
+        }
+    }
+
+    /// Like `new_mouse_event`, but also stamps the event with a click state
+    /// (1 for a single click, 2 for a double-click, 3 for a triple-click).
+    /// `CGEventPost` only treats successive down events as a double/triple
+    /// click when this field is set accordingly.
+    pub fn new_mouse_event_with_click_state(
+        source: CGEventSource,
+        mouse_type: CGEventType,
+        mouse_cursor_position: CGPoint,
+        mouse_button: CGMouseButton,
+        click_state: i64,
+    ) -> Result<CGEvent, ()> {
+        let event = Self::new_mouse_event(source, mouse_type, mouse_cursor_position, mouse_button)?;
+        event.set_integer_value_field(EventField::MOUSE_EVENT_CLICK_STATE, click_state);
+        Ok(event)
+    }
+
+    /// Post a sequence of `clicks` down/up event pairs at `position`,
+    /// stamping each successive pair with the click state needed for the
+    /// system to recognize it as a double- or triple-click rather than
+    /// `clicks` independent single clicks.
+    pub fn post_multi_click(
+        source: CGEventSource,
+        tap_location: CGEventTapLocation,
+        mouse_button: CGMouseButton,
+        position: CGPoint,
+        clicks: u32,
+    ) -> Result<(), ()> {
+        let (down_type, up_type) = match mouse_button {
+            CGMouseButton::Left => (CGEventType::LeftMouseDown, CGEventType::LeftMouseUp),
+            CGMouseButton::Right => (CGEventType::RightMouseDown, CGEventType::RightMouseUp),
+            CGMouseButton::Center => (CGEventType::OtherMouseDown, CGEventType::OtherMouseUp),
+        };
+
+        for click in 1..=clicks {
+            let click_state = click as i64;
+
+            // Build both events before posting either, so a failure to
+            // create the up event can't leave a down event posted with no
+            // matching up event (which would leave the button stuck down).
+            let down = Self::new_mouse_event_with_click_state(
+                source.clone(), down_type, position, mouse_button, click_state,
+            )?;
+            let up = Self::new_mouse_event_with_click_state(
+                source.clone(), up_type, position, mouse_button, click_state,
+            )?;
+
+            down.post(tap_location);
+            up.post(tap_location);
+        }
+
+        Ok(())
+    }
+
     pub fn post(&self, tap_location: CGEventTapLocation) {
         unsafe {
             CGEventPost(tap_location, self.as_ptr());
@@ -169,6 +529,17 @@ impl CGEvent {
         }
     }
 
+    /// Post this event into the stream of an event tap at the point the
+    /// callback was invoked, via the `CGEventTapProxy` the callback
+    /// received. This lets a tap callback synthesize additional events
+    /// in place (e.g. expanding one key into several) without re-entering
+    /// at the global HID tap, which would risk feeding back into the tap.
+    pub fn post_to_event_source(&self, proxy: CGEventTapProxy) {
+        unsafe {
+            CGEventTapPostEvent(proxy, self.as_ptr());
+        }
+    }
+
     pub fn set_flags(&self, flags: CGEventFlags) {
         unsafe {
             CGEventSetFlags(self.as_ptr(), flags);
@@ -204,6 +575,55 @@ impl CGEvent {
         let buf: Vec<u16> = string.encode_utf16().collect();
         self.set_string_from_utf16_unchecked(&buf);
     }
+
+    pub fn get_string(&self) -> String {
+        unsafe {
+            let mut actual_length: libc::c_ulong = 0;
+            CGEventKeyboardGetUnicodeString(self.as_ptr(), 0, &mut actual_length, ptr::null_mut());
+
+            let mut buf: Vec<u16> = Vec::with_capacity(actual_length as usize);
+            CGEventKeyboardGetUnicodeString(
+                self.as_ptr(),
+                actual_length,
+                &mut actual_length,
+                buf.as_mut_ptr(),
+            );
+            buf.set_len(actual_length as usize);
+
+            String::from_utf16_lossy(&buf)
+        }
+    }
+
+    pub fn get_integer_value_field(&self, field: EventField) -> i64 {
+        unsafe {
+            CGEventGetIntegerValueField(self.as_ptr(), field)
+        }
+    }
+
+    pub fn set_integer_value_field(&self, field: EventField, value: i64) {
+        unsafe {
+            CGEventSetIntegerValueField(self.as_ptr(), field, value);
+        }
+    }
+
+    pub fn get_double_value_field(&self, field: EventField) -> f64 {
+        unsafe {
+            CGEventGetDoubleValueField(self.as_ptr(), field)
+        }
+    }
+
+    pub fn set_double_value_field(&self, field: EventField, value: f64) {
+        unsafe {
+            CGEventSetDoubleValueField(self.as_ptr(), field, value);
+        }
+    }
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern {
+    /// Invalidate a `CFMachPort`, unregistering it from any run loops and
+    /// ensuring its callback is never invoked again.
+    fn CFMachPortInvalidate(port: CFMachPortRef);
 }
 
 #[link(name = "ApplicationServices", kind = "framework")]
@@ -244,6 +664,16 @@ extern {
     fn CGEventCreateMouseEvent(source: ::sys::CGEventSourceRef, mouseType: CGEventType,
         mouseCursorPosition: CGPoint, mouseButton: CGMouseButton) -> ::sys::CGEventRef;
 
+    /// Return a new scroll wheel event.
+    ///
+    /// The event source may be taken from another event, or may be NULL.
+    /// `units` specifies whether the wheel deltas are in line or pixel
+    /// units. `wheelCount` is the number of scrolling wheels, from 1 to 3;
+    /// `wheel1`, `wheel2`, and `wheel3` give the associated deltas, with
+    /// `wheel1` being the primary (vertical) axis.
+    fn CGEventCreateScrollWheelEvent2(source: ::sys::CGEventSourceRef, units: ScrollEventUnit,
+        wheelCount: u32, wheel1: i32, wheel2: i32, wheel3: i32) -> ::sys::CGEventRef;
+
     /// Post an event into the event stream at a specified location.
     ///
     /// This function posts the specified event immediately before any event taps
@@ -282,4 +712,77 @@ extern {
     fn CGEventKeyboardSetUnicodeString(event: ::sys::CGEventRef,
                                        length: libc::c_ulong,
                                        string: *const u16);
+
+    /// Return the Unicode string associated with a keyboard event.
+    ///
+    /// Pass 0 for `maxStringLength` and NULL for `unicodeString` to first
+    /// learn the required buffer length via `actualStringLength`, then call
+    /// again with a buffer of that length to retrieve the string itself.
+    fn CGEventKeyboardGetUnicodeString(event: ::sys::CGEventRef,
+                                       maxStringLength: libc::c_ulong,
+                                       actualStringLength: *mut libc::c_ulong,
+                                       unicodeString: *mut u16);
+
+    /// Create a new event tap at a specified point, to intercept a range of
+    /// events. Events are passed in the callback `eventTapCallBack` and
+    /// may be passed through, modified, or discarded by returning the
+    /// appropriate value from that callback.
+    fn CGEventTapCreate(tap: CGEventTapLocation, place: CGEventTapPlacement,
+        options: CGEventTapOptions, eventsOfInterest: CGEventMask,
+        callback: CGEventTapCallBackFn, userInfo: *mut libc::c_void) -> CFMachPortRef;
+
+    /// Enable or disable an event tap.
+    fn CGEventTapEnable(tap: CFMachPortRef, enable: bool);
+
+    /// Post an event to the event stream at the point of an event tap,
+    /// using the `CGEventTapProxy` passed to the tap's callback.
+    fn CGEventTapPostEvent(proxy: CGEventTapProxy, event: ::sys::CGEventRef);
+
+    /// Return the integer value of an event field.
+    fn CGEventGetIntegerValueField(event: ::sys::CGEventRef, field: EventField) -> i64;
+
+    /// Set the integer value of an event field.
+    fn CGEventSetIntegerValueField(event: ::sys::CGEventRef, field: EventField, value: i64);
+
+    /// Return the floating-point value of an event field.
+    fn CGEventGetDoubleValueField(event: ::sys::CGEventRef, field: EventField) -> f64;
+
+    /// Set the floating-point value of an event field.
+    fn CGEventSetDoubleValueField(event: ::sys::CGEventRef, field: EventField, value: f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_field_discriminants_match_cgeventfield() {
+        assert_eq!(EventField::MOUSE_EVENT_NUMBER as u32, 0);
+        assert_eq!(EventField::MOUSE_EVENT_CLICK_STATE as u32, 1);
+        assert_eq!(EventField::MOUSE_EVENT_PRESSURE as u32, 2);
+        assert_eq!(EventField::MOUSE_EVENT_BUTTON_NUMBER as u32, 3);
+        assert_eq!(EventField::MOUSE_EVENT_DELTA_X as u32, 4);
+        assert_eq!(EventField::MOUSE_EVENT_DELTA_Y as u32, 5);
+        assert_eq!(EventField::MOUSE_EVENT_INSTANT_MOUSER as u32, 6);
+        assert_eq!(EventField::MOUSE_EVENT_SUBTYPE as u32, 7);
+        assert_eq!(EventField::KEYBOARD_EVENT_AUTOREPEAT as u32, 8);
+        assert_eq!(EventField::KEYBOARD_EVENT_KEYCODE as u32, 9);
+        assert_eq!(EventField::KEYBOARD_EVENT_KEYBOARD_TYPE as u32, 10);
+        assert_eq!(EventField::SCROLL_WHEEL_EVENT_DELTA_AXIS_1 as u32, 11);
+        assert_eq!(EventField::SCROLL_WHEEL_EVENT_DELTA_AXIS_2 as u32, 12);
+        assert_eq!(EventField::SCROLL_WHEEL_EVENT_DELTA_AXIS_3 as u32, 13);
+        assert_eq!(EventField::SCROLL_WHEEL_EVENT_FIXED_PT_DELTA_AXIS_1 as u32, 93);
+        assert_eq!(EventField::SCROLL_WHEEL_EVENT_FIXED_PT_DELTA_AXIS_2 as u32, 94);
+        assert_eq!(EventField::SCROLL_WHEEL_EVENT_FIXED_PT_DELTA_AXIS_3 as u32, 95);
+        assert_eq!(EventField::EVENT_TARGET_UNIX_PROCESS_ID as u32, 40);
+        assert_eq!(EventField::EVENT_SOURCE_UNIX_PROCESS_ID as u32, 41);
+        assert_eq!(EventField::EVENT_SOURCE_USER_DATA as u32, 42);
+    }
+
+    #[test]
+    fn event_mask_bit() {
+        assert_eq!(CGEventMaskBit(CGEventType::KeyDown), 1 << 10);
+        assert_eq!(CGEventMaskBit(CGEventType::TapDisabledByTimeout), 0);
+        assert_eq!(CGEventMaskBit(CGEventType::TapDisabledByUserInput), 0);
+    }
 }